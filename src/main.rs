@@ -1,15 +1,22 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Seek};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use clap::{Parser, Subcommand, ValueEnum};
+use fst::{automaton::Levenshtein, IntoStreamer, Map as FstMap, MapBuilder, Streamer};
+use serde_json::{json, Value};
 
 use lib_gddb::arc::Archive;
 use lib_gddb::arz::{Database, DatabaseValue, RawRecord, Record};
 use lib_gddb::tags;
 
+/// Default Levenshtein edit distance for fuzzy `item` lookups; `--fuzzy` overrides it.
+const DEFAULT_FUZZY_DISTANCE: u32 = 1;
+
+const TAG_INDEX_FILE: &str = "gddb_tag_index.bin";
+
 const DB_GD: &str = "database/database.arz";
 const DB_AOM: &str = "gdx1/database/GDX1.arz";
 const DB_FG: &str = "gdx2/database/GDX2.arz";
@@ -31,10 +38,22 @@ struct Args {
     /// Restrict lookup to database for nth expansion (0 = base game)
     xpac: Option<usize>,
 
+    #[arg(long, global = true, default_value_t, value_enum)]
+    /// Output format for show/item/ls/loot_table
+    format: OutputFormat,
+
     #[command(subcommand)]
     cmd: Action,
 }
 
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Ndjson,
+}
+
 #[derive(Default, Debug, Clone, Copy, ValueEnum)]
 enum Difficulty {
     Normal,
@@ -56,9 +75,27 @@ enum Action {
         path: OsString,
     },
     /// Look up an item by name and list the records it appears in.
-    Item { name: OsString },
+    Item {
+        name: OsString,
+        #[arg(long)]
+        /// Max Levenshtein edit distance for typo-tolerant matching (default 1).
+        fuzzy: Option<u32>,
+    },
     /// Show the next level of the file tree, starting at the provided path.
     Ls { path: Option<OsString> },
+    /// Run a small relational query over every record's (record_id, field, value) triples.
+    ///
+    /// `[from <id-prefix>] <select|count|min|max|sum|avg>(<field path>) [where <field path>
+    /// <op> <value> [and ...]] [group by <field path>]`, where a field path like
+    /// `skillName.buffName.damageType` hops through reference-valued fields from one
+    /// record to another before reading the final field. Example:
+    /// `from records/items count(*) group by itemClassification`.
+    Query { query: String },
+    /// Find every record whose fields, taken together, mention all of the given terms.
+    Search {
+        #[arg(required = true)]
+        terms: Vec<String>,
+    },
     /// Print the specified database record.
     Show { path: OsString },
 }
@@ -71,96 +108,606 @@ fn main() {
     let mut dbs = open_dbs(install_path.clone(), args.xpac);
 
     let item_tags = read_item_tags(install_path.clone());
+    let record_index = build_record_index(dbs.as_mut_slice());
 
     match args.cmd {
         Action::LootTable {
-            path, difficulty, ..
-        } => loot_table(dbs.as_mut_slice(), item_tags, path, difficulty),
-        Action::Item { name } => item(dbs.as_mut_slice(), item_tags, name),
-        Action::Ls { path } => ls(dbs.as_mut_slice(), path),
-        Action::Show { path } => show(dbs.as_mut_slice(), path),
+            path,
+            difficulty,
+            vendor,
+        } => loot_table(&record_index, item_tags, path, difficulty, vendor, args.format),
+        Action::Item { name, fuzzy } => item(
+            &record_index,
+            install_path,
+            item_tags,
+            name,
+            fuzzy.unwrap_or(DEFAULT_FUZZY_DISTANCE),
+            args.format,
+        ),
+        Action::Ls { path } => ls(&record_index, path, args.format),
+        Action::Query { query } => run_query(&record_index, query),
+        Action::Search { terms } => run_search(dbs.as_mut_slice(), &install_path, args.xpac, terms),
+        Action::Show { path } => show(&record_index, path, args.format),
     }
 }
 
-fn loot_table<T: BufRead + Seek>(
-    arz: &mut [Database<T>],
+/// Each loot table record is scanned for `lootName{n}`/`lootWeight{n}` slots numbered
+/// 1 through this many; a record with none of them is treated as a leaf. This bounds a
+/// table's width, not the nesting depth; matches the widest tables seen in practice.
+const MAX_LOOT_SLOTS: usize = 40;
+
+fn loot_table(
+    index: &RecordIndex,
     tags: HashMap<String, String>,
     record: OsString,
     difficulty: Difficulty,
+    vendor: bool,
+    format: OutputFormat,
 ) {
-    let loot_table = get_record(arz, record);
-    let affixes = iter_records(arz, |_, raw| raw.kind == "lootRandomizer");
-    print!("{loot_table}");
+    let record_id = record.to_string_lossy().into_owned();
+    let mut visiting = HashSet::new();
+    let mut memo = HashMap::new();
+    let mut distribution = resolve_loot_table(index, difficulty, vendor, &record_id, &mut visiting, &mut memo);
+    distribution.sort_by(|(a_id, a_p), (b_id, b_p)| {
+        b_p.partial_cmp(a_p)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a_id.cmp(b_id))
+    });
+
+    match format {
+        OutputFormat::Text => {
+            for (item, probability) in &distribution {
+                match resolve_item_name(index, &tags, item) {
+                    Some(name) => println!("{probability:>8.4}  {item}  ({name})"),
+                    None => println!("{probability:>8.4}  {item}"),
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let rows = distribution
+                .iter()
+                .map(|(item, probability)| {
+                    let name = resolve_item_name(index, &tags, item);
+                    json!({ "item": item, "name": name, "probability": probability })
+                })
+                .collect::<Vec<_>>();
+            println!("{}", Value::Array(rows));
+        }
+        OutputFormat::Ndjson => {
+            for (item, probability) in &distribution {
+                let name = resolve_item_name(index, &tags, item);
+                println!("{}", json!({ "item": item, "name": name, "probability": probability }));
+            }
+        }
+    }
+}
+
+/// Recursively expand a loot master/dynamic table down to leaf item records, multiplying
+/// each level's normalized weight along the path. Memoized per record id so shared
+/// subtrees (e.g. a common affix table referenced from many containers) are only
+/// expanded once; `visiting` breaks reference cycles by treating a repeat as a dead end.
+fn resolve_loot_table(
+    index: &RecordIndex,
+    difficulty: Difficulty,
+    vendor: bool,
+    record_id: &str,
+    visiting: &mut HashSet<String>,
+    memo: &mut HashMap<String, Vec<(String, f64)>>,
+) -> Vec<(String, f64)> {
+    let mut children = |id: &str| -> Vec<(String, f64)> {
+        find_record(index, id)
+            .map(|record| loot_entries(record, difficulty, vendor))
+            .unwrap_or_default()
+    };
+    resolve_distribution(record_id, visiting, memo, &mut children).0
 }
 
-fn item<T: BufRead + Seek>(arz: &mut [Database<T>], tags: HashMap<String, String>, item: OsString) {
-    let (name, ids) = lookup_item_ids(arz, &tags, item);
-    println!("{name} is referenced in the following database records:");
-    for record in ids {
-        println!("  {record}");
+/// Recursively expand `record_id` into a leaf distribution using `children` to fetch one
+/// level of (child_id, weight) pairs (an empty result means `record_id` is a leaf).
+/// Returns the distribution alongside whether it's safe to memoize. A result is only
+/// cacheable if no descendant expansion bottomed out by hitting an ancestor already on
+/// `visiting` — otherwise the same record reached from a *different* path (where that
+/// ancestor isn't actually in the way) would wrongly get handed the truncated result.
+/// Pulled apart from the `Database`-specific lookup so the cycle/memo logic itself can
+/// be exercised directly in tests against a plain in-memory graph.
+fn resolve_distribution<F>(
+    record_id: &str,
+    visiting: &mut HashSet<String>,
+    memo: &mut HashMap<String, Vec<(String, f64)>>,
+    children: &mut F,
+) -> (Vec<(String, f64)>, bool)
+where
+    F: FnMut(&str) -> Vec<(String, f64)>,
+{
+    if let Some(cached) = memo.get(record_id) {
+        return (cached.clone(), true);
     }
+    if visiting.contains(record_id) {
+        return (Vec::new(), false);
+    }
+    visiting.insert(record_id.to_string());
+
+    let entries = children(record_id);
+    let (distribution, cacheable) = if entries.is_empty() {
+        (vec![(record_id.to_string(), 1.0)], true)
+    } else {
+        let total_weight: f64 = entries.iter().map(|(_, weight)| weight).sum();
+        let mut merged: HashMap<String, f64> = HashMap::new();
+        let mut cacheable = true;
+        for (child_id, weight) in &entries {
+            if total_weight <= 0.0 {
+                continue;
+            }
+            let local_probability = weight / total_weight;
+            let (child_distribution, child_cacheable) =
+                resolve_distribution(child_id, visiting, memo, children);
+            cacheable &= child_cacheable;
+            for (item, probability) in child_distribution {
+                *merged.entry(item).or_insert(0.0) += local_probability * probability;
+            }
+        }
+        (merged.into_iter().collect(), cacheable)
+    };
+
+    visiting.remove(record_id);
+    if cacheable {
+        memo.insert(record_id.to_string(), distribution.clone());
+    }
+    (distribution, cacheable)
 }
 
-fn lookup_item_ids<T: BufRead + Seek>(
-    arz: &mut [Database<T>],
+/// Pull this record's child table/item references and their weights for one level of
+/// loot expansion. Both *which record* a slot points at and its weight can be
+/// overridden: `--vendor` prefers a `vendorLootName{n}`/`vendorLootRandomizerTable`
+/// reference over the normal one (substituting the vendor affix table), and a
+/// difficulty prefers `lootName{n}{difficulty}` over the base `lootName{n}` when present.
+fn loot_entries(record: &Record, difficulty: Difficulty, vendor: bool) -> Vec<(String, f64)> {
+    let difficulty_suffix = match difficulty {
+        Difficulty::Normal => "",
+        Difficulty::Elite => "Elite",
+        Difficulty::Ultimate => "Ultimate",
+    };
+
+    let mut entries = Vec::new();
+    for n in 1..=MAX_LOOT_SLOTS {
+        let Some(child_id) = loot_child_id(record, n, difficulty_suffix, vendor) else {
+            continue;
+        };
+        let weight = loot_weight(record, n, difficulty_suffix, vendor);
+        if weight > 0.0 {
+            entries.push((child_id, weight));
+        }
+    }
+    let randomizer_field = if vendor {
+        "vendorLootRandomizerTable"
+    } else {
+        "lootRandomizerTable"
+    };
+    let randomizer =
+        field_as_string(record, randomizer_field).or_else(|| field_as_string(record, "lootRandomizerTable"));
+    if let Some(randomizer) = randomizer {
+        let weight = field_as_f64(record, "dynWeight").unwrap_or(1.0);
+        if weight > 0.0 {
+            entries.push((randomizer, weight));
+        }
+    }
+    entries
+}
+
+/// Resolve which record slot `n` actually points at, preferring (in order) the vendor
+/// affix-table override, then the difficulty-specific override, then the base field.
+fn loot_child_id(record: &Record, n: usize, difficulty_suffix: &str, vendor: bool) -> Option<String> {
+    if vendor {
+        if let Some(child_id) = field_as_string(record, &format!("vendorLootName{n}")) {
+            return Some(child_id);
+        }
+    }
+    if !difficulty_suffix.is_empty() {
+        if let Some(child_id) = field_as_string(record, &format!("lootName{n}{difficulty_suffix}")) {
+            return Some(child_id);
+        }
+    }
+    field_as_string(record, &format!("lootName{n}"))
+}
+
+fn loot_weight(record: &Record, n: usize, difficulty_suffix: &str, vendor: bool) -> f64 {
+    if vendor {
+        if let Some(weight) = field_as_f64(record, &format!("vendorWeight{n}")) {
+            return weight;
+        }
+    }
+    if !difficulty_suffix.is_empty() {
+        if let Some(weight) = field_as_f64(record, &format!("lootWeight{n}{difficulty_suffix}")) {
+            return weight;
+        }
+    }
+    field_as_f64(record, &format!("lootWeight{n}")).unwrap_or(1.0)
+}
+
+fn field_as_string(record: &Record, field: &str) -> Option<String> {
+    let text = record.data.get(field)?.to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+fn field_as_f64(record: &Record, field: &str) -> Option<f64> {
+    record.data.get(field)?.to_string().parse().ok()
+}
+
+/// A one-time decompress-and-parse pass over every open database, sorted by id so
+/// every lookup afterward is a binary search instead of a fresh linear scan. Unlike
+/// `TagIndex`/`SearchIndex` this isn't cached to a sidecar file: `Record` and
+/// `DatabaseValue` are opaque types owned by `lib_gddb`, and without a stable wire
+/// format for every variant we'd rather re-scan once per run than risk silently
+/// misparsing a cached field on the next invocation.
+struct RecordIndex {
+    records: Vec<Record>,
+}
+
+impl RecordIndex {
+    fn get(&self, id: &str) -> Option<&Record> {
+        let at = self.records.binary_search_by(|r| r.id.as_str().cmp(id)).ok()?;
+        self.records.get(at)
+    }
+
+    /// Ids starting with `prefix`, in sorted order: a `partition_point` binary search
+    /// to the first candidate, then a bounded scan instead of a full linear filter.
+    fn ids_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        let start = self.records.partition_point(|r| r.id.as_str() < prefix);
+        self.records[start..]
+            .iter()
+            .map(|r| r.id.as_str())
+            .take_while(move |id| id.starts_with(prefix))
+    }
+}
+
+/// Build the record index with one full pass over every open database, keeping the
+/// last copy seen when the same id appears in more than one (matching the original
+/// linear `get_record`'s "later database wins" behavior, since `records_by_xpac`
+/// visits the base game and each expansion in install order).
+fn build_record_index<T: BufRead + Seek>(arz: &mut [Database<T>]) -> RecordIndex {
+    let mut by_id: BTreeMap<String, Record> = BTreeMap::new();
+    for record in records_by_xpac(arz, |_, _| true).into_iter().flatten() {
+        by_id.insert(record.id.clone(), record);
+    }
+    RecordIndex { records: by_id.into_values().collect() }
+}
+
+/// Look up a record by id without the `show`/`get_record` behavior of exiting the
+/// process when it's missing; loot resolution treats a dangling reference as a
+/// dead-end leaf rather than aborting the whole table.
+fn find_record<'a>(index: &'a RecordIndex, id: &str) -> Option<&'a Record> {
+    index.get(id)
+}
+
+fn resolve_item_name(index: &RecordIndex, tags: &HashMap<String, String>, record_id: &str) -> Option<String> {
+    let record = find_record(index, record_id)?;
+    let tag = field_as_string(record, "itemNameTag")?;
+    tags.get(&tag).cloned()
+}
+
+fn item(
+    record_index: &RecordIndex,
+    install_path: PathBuf,
+    tags: HashMap<String, String>,
+    item: OsString,
+    fuzzy: u32,
+    format: OutputFormat,
+) {
+    let tag_index = load_or_build_tag_index(&install_path, &tags);
+    let (name, ids) = lookup_item_ids(record_index, &tags, &tag_index, item, fuzzy, format);
+    match format {
+        OutputFormat::Text => {
+            println!("{name} is referenced in the following database records:");
+            for record in ids {
+                println!("  {record}");
+            }
+        }
+        OutputFormat::Json => {
+            let records = ids.into_iter().collect::<Vec<_>>();
+            println!("{}", json!({ "name": name, "records": records }));
+        }
+        OutputFormat::Ndjson => {
+            for record in ids {
+                println!("{}", json!({ "name": name, "record": record }));
+            }
+        }
+    }
+}
+
+/// A word-level search index over tag values: each indexed word maps (through
+/// `postings`) to the tags whose value contains it, so `item` can find a value by
+/// any of its words within a bounded edit distance instead of scanning every tag.
+struct TagIndex {
+    words: FstMap<Vec<u8>>,
+    postings: Vec<Vec<String>>,
+}
+
+fn tag_index_path(install_path: &Path) -> PathBuf {
+    install_path.join(TAG_INDEX_FILE)
+}
+
+fn build_tag_index(tags: &HashMap<String, String>) -> TagIndex {
+    let mut words: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (tag, value) in tags {
+        for word in value.to_lowercase().split_whitespace() {
+            let postings = words.entry(word.to_string()).or_default();
+            if !postings.contains(tag) {
+                postings.push(tag.clone());
+            }
+        }
+    }
+    let mut builder = MapBuilder::memory();
+    let mut postings = Vec::with_capacity(words.len());
+    for (word, tags) in words {
+        builder
+            .insert(&word, postings.len() as u64)
+            .expect("words are inserted in sorted order");
+        postings.push(tags);
+    }
+    let bytes = builder.into_inner().expect("fst builder finishes cleanly");
+    let words = FstMap::new(bytes).expect("just-built fst bytes form a valid map");
+    TagIndex { words, postings }
+}
+
+fn save_tag_index(path: &Path, index: &TagIndex) -> std::io::Result<()> {
+    let fst_bytes = index.words.as_fst().as_bytes();
+    let mut out = Vec::with_capacity(fst_bytes.len() + index.postings.len() * 16);
+    out.extend_from_slice(&(fst_bytes.len() as u64).to_le_bytes());
+    out.extend_from_slice(fst_bytes);
+    for tags in &index.postings {
+        let joined = tags.join("\u{1}");
+        out.extend_from_slice(&(joined.len() as u64).to_le_bytes());
+        out.extend_from_slice(joined.as_bytes());
+    }
+    std::fs::write(path, out)
+}
+
+fn load_tag_index(path: &Path) -> std::io::Result<TagIndex> {
+    let bytes = std::fs::read(path)?;
+    let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+
+    let mut cursor = &bytes[..];
+    let fst_len = read_u64(&mut cursor)? as usize;
+    let fst_bytes = cursor.get(..fst_len).ok_or_else(|| invalid("truncated tag index"))?;
+    let words = FstMap::new(fst_bytes.to_vec()).map_err(invalid)?;
+    cursor = &cursor[fst_len..];
+
+    let mut postings = Vec::new();
+    while !cursor.is_empty() {
+        let len = read_u64(&mut cursor)? as usize;
+        let raw = cursor.get(..len).ok_or_else(|| invalid("truncated tag index"))?;
+        let joined = std::str::from_utf8(raw).map_err(invalid)?;
+        postings.push(joined.split('\u{1}').filter(|s| !s.is_empty()).map(str::to_string).collect());
+        cursor = &cursor[len..];
+    }
+    Ok(TagIndex { words, postings })
+}
+
+fn read_u64(cursor: &mut &[u8]) -> std::io::Result<u64> {
+    if cursor.len() < 8 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "truncated tag index",
+        ));
+    }
+    let (len_bytes, rest) = cursor.split_at(8);
+    *cursor = rest;
+    Ok(u64::from_le_bytes(len_bytes.try_into().expect("split_at(8) yields 8 bytes")))
+}
+
+/// Load the tag search index from its sidecar next to the install, building and
+/// caching it on first use so later lookups skip the tokenization pass entirely.
+fn load_or_build_tag_index(install_path: &Path, tags: &HashMap<String, String>) -> TagIndex {
+    let path = tag_index_path(install_path);
+    let source_paths = [TAGS_GD, TAGS_AOM, TAGS_FG, TAGS_FOA]
+        .iter()
+        .map(|name| install_path.join(name))
+        .collect::<Vec<_>>();
+    let index_mtime = std::fs::metadata(&path).ok().and_then(|meta| meta.modified().ok());
+    if let (Some(index_mtime), Some(newest_source)) = (index_mtime, newest_mtime(&source_paths)) {
+        if index_mtime >= newest_source {
+            if let Ok(index) = load_tag_index(&path) {
+                return index;
+            }
+        }
+    }
+    let index = build_tag_index(tags);
+    if let Err(e) = save_tag_index(&path, &index) {
+        eprintln!("Warning: could not write tag index cache to {}: {e}", path.display());
+    }
+    index
+}
+
+fn levenshtein_distance(a: &str, b: &str) -> usize {
+    let a = a.as_bytes();
+    let b = b.as_bytes();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, &ca) in a.iter().enumerate() {
+        let mut prev_diag = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == cb {
+                prev_diag
+            } else {
+                1 + prev_diag.min(row[j]).min(row[j + 1])
+            };
+            prev_diag = cur;
+        }
+    }
+    row[b.len()]
+}
+
+/// Find every tag whose value contains a near-match (within `max_distance` edits) of
+/// each whitespace-separated word in the query, ranked exact > prefix > closer > shorter.
+/// A word for which `Levenshtein::new` cannot build an automaton is skipped, not required.
+fn candidate_tags<'a>(
+    tags: &'a HashMap<String, String>,
+    index: &TagIndex,
+    query: &str,
+    max_distance: u32,
+) -> Vec<(&'a String, &'a String)> {
+    let query_words = query
+        .to_lowercase()
+        .split_whitespace()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    if query_words.is_empty() {
+        return vec![];
+    }
+
+    let mut candidate_tags: Option<HashSet<&String>> = None;
+    for word in &query_words {
+        let Ok(automaton) = Levenshtein::new(word, max_distance) else {
+            continue;
+        };
+        let mut matched = HashSet::new();
+        let mut stream = index.words.search(automaton).into_stream();
+        while let Some((_, posting_id)) = stream.next() {
+            matched.extend(index.postings[posting_id as usize].iter());
+        }
+        candidate_tags = Some(match candidate_tags {
+            Some(existing) => existing.intersection(&matched).copied().collect(),
+            None => matched,
+        });
+    }
+
+    let mut candidates: Vec<(&String, &String)> = candidate_tags
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|tag| tags.get_key_value(tag.as_str()))
+        .collect();
+
+    let query_lower = query.to_lowercase();
+    candidates.sort_by_key(|(_, value)| {
+        let lower = value.to_lowercase();
+        let exact = lower == query_lower;
+        let prefix = lower.starts_with(&query_lower);
+        let distance = levenshtein_distance(&lower, &query_lower);
+        (
+            std::cmp::Reverse(exact),
+            std::cmp::Reverse(prefix),
+            distance,
+            value.len(),
+        )
+    });
+    candidates
+}
+
+fn print_no_matches(format: OutputFormat) {
+    match format {
+        OutputFormat::Text => eprintln!("No matching items found"),
+        OutputFormat::Json | OutputFormat::Ndjson => {
+            println!("{}", json!({ "error": "No matching items found" }))
+        }
+    }
+}
+
+fn print_ambiguous_matches(format: OutputFormat, possible_tags: &[(&String, &String)]) {
+    match format {
+        OutputFormat::Text => {
+            println!("Multiple item tags found, please disambiguate:");
+            for (_, value) in possible_tags {
+                println!("  {value}");
+            }
+        }
+        OutputFormat::Json => {
+            let candidates = possible_tags.iter().map(|(_, v)| v).collect::<Vec<_>>();
+            println!(
+                "{}",
+                json!({ "error": "Multiple item tags found, please disambiguate", "candidates": candidates })
+            );
+        }
+        OutputFormat::Ndjson => {
+            for (_, value) in possible_tags {
+                println!("{}", json!({ "error": "ambiguous", "candidate": value }));
+            }
+        }
+    }
+}
+
+fn lookup_item_ids(
+    index: &RecordIndex,
     tags: &HashMap<String, String>,
+    tag_index: &TagIndex,
     item: OsString,
+    max_distance: u32,
+    format: OutputFormat,
 ) -> (String, HashSet<String>) {
     let item = item.to_string_lossy();
-    let item_parts = item.split_ascii_whitespace().collect::<Vec<_>>();
-    let mut possible_tags = vec![];
-    for (tag, value) in tags.iter() {
-        if item_parts.iter().all(|part| value.contains(part)) {
-            possible_tags.push((tag, value));
-        }
-    }
+    let mut possible_tags = candidate_tags(tags, tag_index, &item, max_distance);
     if possible_tags.is_empty() {
-        eprintln!("No matching items found");
+        print_no_matches(format);
         std::process::exit(0);
     } else if possible_tags.len() > 1 {
         if let Some(exact_match) = possible_tags.iter().find(|(_, v)| **v == item) {
             possible_tags = vec![*exact_match];
         } else {
             possible_tags.sort_by_key(|(_, v)| *v);
-            println!("Multiple item tags found, please disambiguate:");
-            for (_, value) in possible_tags.iter() {
-                println!("  {value}");
-            }
+            print_ambiguous_matches(format, &possible_tags);
             std::process::exit(0);
         }
     }
     let (tag, name) = possible_tags.pop().expect("possible_tags.len() == 1");
     let tag = DatabaseValue::String(tag.to_string());
-    let ids = iter_records(arz, |id, _raw| id.starts_with("records/items"))
-        .filter(|record| record.data.get("itemNameTag") == Some(&tag))
-        .map(|record| record.id)
+    let ids = index
+        .ids_with_prefix("records/items")
+        .filter(|id| index.get(id).is_some_and(|record| record.data.get("itemNameTag") == Some(&tag)))
+        .map(str::to_string)
         .collect::<HashSet<_>>();
 
     (name.to_string(), ids)
 }
 
-fn get_record<T: BufRead + Seek>(arz: &mut [Database<T>], matches: OsString) -> Record {
+fn get_record<'a>(index: &'a RecordIndex, matches: OsString) -> &'a Record {
     let needle = matches.to_string_lossy();
-    let mut matches = iter_records(arz, |id, _| id == needle).collect::<Vec<_>>();
-    if matches.is_empty() {
-        eprintln!("not found: {needle}");
-        std::process::exit(1);
-    } else if matches.len() > 1 {
-        eprintln!(
-            "WARN: {} records found for {}; showing latest",
-            matches.len(),
-            needle
-        );
+    match index.get(&needle) {
+        Some(record) => record,
+        None => {
+            eprintln!("not found: {needle}");
+            std::process::exit(1);
+        }
     }
-    matches.pop().expect("record.len() > 0")
 }
 
-fn show<T: BufRead + Seek>(arz: &mut [Database<T>], record: OsString) {
-    print!("{}", get_record(arz, record));
+fn show(index: &RecordIndex, record: OsString, format: OutputFormat) {
+    let record = get_record(index, record);
+    match format {
+        OutputFormat::Text => print!("{record}"),
+        OutputFormat::Json | OutputFormat::Ndjson => println!("{}", record_to_json(record)),
+    }
 }
 
-fn ls<T: BufRead + Seek>(arz: &mut [Database<T>], prefix: Option<OsString>) {
-    let nexts = iter_record_ids(arz)
+/// JSON-ify a record, preserving each field's native type instead of the stringified
+/// form `Display` produces, so downstream tools like `jq` get real numbers/arrays.
+fn record_to_json(record: &Record) -> Value {
+    let fields = record
+        .data
+        .iter()
+        .map(|(field, value)| (field.clone(), value_to_json(value)))
+        .collect::<serde_json::Map<_, _>>();
+    json!({ "id": record.id, "fields": fields })
+}
+
+fn value_to_json(value: &DatabaseValue) -> Value {
+    match value {
+        DatabaseValue::String(s) => Value::String(s.clone()),
+        DatabaseValue::Int(i) => json!(i),
+        DatabaseValue::Float(f) => json!(f),
+        DatabaseValue::Array(items) => Value::Array(items.iter().map(value_to_json).collect()),
+    }
+}
+
+fn ls(index: &RecordIndex, prefix: Option<OsString>, format: OutputFormat) {
+    let prefix_str = prefix.as_ref().map(|p| p.to_string_lossy().into_owned());
+    // Binary search straight to the matching range in the sorted record index instead
+    // of enumerating and filtering every id in every database.
+    let ids = index
+        .ids_with_prefix(prefix_str.as_deref().unwrap_or(""))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    let nexts = ids
+        .into_iter()
         .filter_map(|id| {
             let path = PathBuf::from(&id);
             let path = match &prefix {
@@ -183,38 +730,603 @@ fn ls<T: BufRead + Seek>(arz: &mut [Database<T>], prefix: Option<OsString>) {
         sorted.push(path);
     }
     sorted.sort();
-    for path in sorted {
-        println!("{path}");
+    match format {
+        OutputFormat::Text => {
+            for path in sorted {
+                println!("{path}");
+            }
+        }
+        OutputFormat::Json => println!("{}", json!(sorted)),
+        OutputFormat::Ndjson => {
+            for path in sorted {
+                println!("{}", json!(path));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Aggregation {
+    Select,
+    Count,
+    Min,
+    Max,
+    Sum,
+    Avg,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug)]
+struct Predicate {
+    field: String,
+    op: CompareOp,
+    value: String,
+}
+
+#[derive(Debug)]
+struct Query {
+    from_prefix: Option<String>,
+    aggregation: Aggregation,
+    field: String,
+    predicates: Vec<Predicate>,
+    group_by: Option<String>,
+}
+
+/// Splits a query string into whitespace-delimited tokens, treating `(`, `)` and the
+/// comparison operators as tokens in their own right even when typed with no
+/// surrounding space (`count(*)`), and double-quoted text as a single token so values
+/// containing spaces (`itemNameTag = "Boots of the Whale"`) survive intact.
+fn tokenize(query: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut chars = query.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                let mut quoted = String::new();
+                for c in chars.by_ref() {
+                    if c == '"' {
+                        break;
+                    }
+                    quoted.push(c);
+                }
+                tokens.push(quoted);
+            }
+            '(' | ')' => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                tokens.push(c.to_string());
+            }
+            '=' | '<' | '>' | '!' => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                let mut op = c.to_string();
+                if chars.peek() == Some(&'=') {
+                    op.push(chars.next().expect("peek confirmed a char is present"));
+                }
+                tokens.push(op);
+            }
+            c if c.is_whitespace() => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+fn parse_query(tokens: &[String]) -> Result<Query, String> {
+    let mut pos = 0;
+    let at = |pos: usize| tokens.get(pos).map(String::as_str);
+
+    let mut from_prefix = None;
+    if at(pos) == Some("from") {
+        pos += 1;
+        from_prefix = Some(
+            tokens
+                .get(pos)
+                .ok_or("expected an id prefix after `from`")?
+                .clone(),
+        );
+        pos += 1;
+    }
+
+    let aggregation = match at(pos) {
+        Some("select") => Aggregation::Select,
+        Some("count") => Aggregation::Count,
+        Some("min") => Aggregation::Min,
+        Some("max") => Aggregation::Max,
+        Some("sum") => Aggregation::Sum,
+        Some("avg") => Aggregation::Avg,
+        other => {
+            return Err(format!(
+                "expected one of select/count/min/max/sum/avg, found {other:?}"
+            ))
+        }
+    };
+    pos += 1;
+
+    if at(pos) != Some("(") {
+        return Err("expected `(` after the aggregation".to_string());
+    }
+    pos += 1;
+    let field = tokens.get(pos).ok_or("expected a field inside `( )`")?.clone();
+    pos += 1;
+    if at(pos) != Some(")") {
+        return Err("expected `)` to close the field".to_string());
+    }
+    pos += 1;
+
+    let mut predicates = Vec::new();
+    if at(pos) == Some("where") {
+        pos += 1;
+        loop {
+            let field = tokens
+                .get(pos)
+                .ok_or("expected a field in the `where` clause")?
+                .clone();
+            pos += 1;
+            let op = match at(pos) {
+                Some("=") => CompareOp::Eq,
+                Some("!=") => CompareOp::Ne,
+                Some("<") => CompareOp::Lt,
+                Some("<=") => CompareOp::Le,
+                Some(">") => CompareOp::Gt,
+                Some(">=") => CompareOp::Ge,
+                other => return Err(format!("expected a comparison operator, found {other:?}")),
+            };
+            pos += 1;
+            let value = tokens
+                .get(pos)
+                .ok_or("expected a value to compare against")?
+                .clone();
+            pos += 1;
+            predicates.push(Predicate { field, op, value });
+            if at(pos) == Some("and") {
+                pos += 1;
+                continue;
+            }
+            break;
+        }
     }
+
+    let mut group_by = None;
+    if at(pos) == Some("group") {
+        pos += 1;
+        if at(pos) != Some("by") {
+            return Err("expected `by` after `group`".to_string());
+        }
+        pos += 1;
+        group_by = Some(
+            tokens
+                .get(pos)
+                .ok_or("expected a field after `group by`")?
+                .clone(),
+        );
+        pos += 1;
+    }
+
+    if pos != tokens.len() {
+        return Err(format!("unexpected trailing tokens: {:?}", &tokens[pos..]));
+    }
+
+    if matches!(aggregation, Aggregation::Select) && group_by.is_some() {
+        return Err("`group by` is not supported with `select`".to_string());
+    }
+
+    Ok(Query {
+        from_prefix,
+        aggregation,
+        field,
+        predicates,
+        group_by,
+    })
+}
+
+/// Resolve a dotted field path against `start`, hopping to another record each time a
+/// non-final segment's value is itself a record id (a reference-valued field), so a path
+/// like `skillName.buffName.damageType` performs the join implied by following those
+/// references. `*` as the final segment yields the current record's id.
+fn resolve_path<'a>(index: &'a RecordIndex, start: &'a Record, path: &str) -> Option<String> {
+    let mut segments: Vec<&str> = path.split('.').collect();
+    let last = segments.pop()?;
+
+    let mut hop: Option<&Record> = None;
+    for segment in segments {
+        let current = hop.unwrap_or(start);
+        let reference_id = current.data.get(segment)?.to_string();
+        hop = Some(find_record(index, &reference_id)?);
+    }
+
+    let current = hop.unwrap_or(start);
+    if last == "*" {
+        return Some(current.id.clone());
+    }
+    let value = current.data.get(last)?.to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+fn resolve_numeric_path(index: &RecordIndex, record: &Record, path: &str) -> Option<f64> {
+    resolve_path(index, record, path)?.parse().ok()
+}
+
+fn matches_op(op: CompareOp, ordering: std::cmp::Ordering) -> bool {
+    use std::cmp::Ordering::*;
+    match op {
+        CompareOp::Eq => ordering == Equal,
+        CompareOp::Ne => ordering != Equal,
+        CompareOp::Lt => ordering == Less,
+        CompareOp::Le => ordering != Greater,
+        CompareOp::Gt => ordering == Greater,
+        CompareOp::Ge => ordering != Less,
+    }
+}
+
+fn eval_predicate(index: &RecordIndex, record: &Record, predicate: &Predicate) -> bool {
+    let Some(actual) = resolve_path(index, record, &predicate.field) else {
+        return false;
+    };
+    let ordering = match (actual.parse::<f64>(), predicate.value.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b),
+        _ => Some(actual.as_str().cmp(predicate.value.as_str())),
+    };
+    ordering.is_some_and(|ordering| matches_op(predicate.op, ordering))
+}
+
+/// Returns `None` when `values` is empty rather than letting `Min`/`Max` fold over an
+/// empty slice into `inf`/`-inf` — there's no sensible numeric result when nothing
+/// matched the query, so callers print a "no results" marker instead.
+fn fold_aggregate(aggregation: Aggregation, values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    Some(match aggregation {
+        Aggregation::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+        Aggregation::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        Aggregation::Sum => values.iter().sum(),
+        Aggregation::Avg => values.iter().sum::<f64>() / values.len() as f64,
+        Aggregation::Count | Aggregation::Select => {
+            unreachable!("count/select are handled without fold_aggregate")
+        }
+    })
+}
+
+/// Evaluate a `query` DSL string: scan the (optionally id-prefix-scoped) record index,
+/// apply `where` predicates with a binary-search lookup in the sorted record index for
+/// each reference hop, then
+/// fold the surviving rows through the aggregation, grouped by `group by` when present.
+/// This is the generalized engine `lookup_item_ids`'s hand-rolled filtering was a
+/// one-off special case of.
+fn run_query(index: &RecordIndex, query: String) {
+    let tokens = tokenize(&query);
+    let parsed = match parse_query(&tokens) {
+        Ok(query) => query,
+        Err(e) => {
+            eprintln!("invalid query: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let prefix = parsed.from_prefix.clone();
+    let matched = index
+        .records
+        .iter()
+        .filter(|record| match &prefix {
+            Some(prefix) => record.id.starts_with(prefix.as_str()),
+            None => true,
+        })
+        .filter(|record| {
+            parsed
+                .predicates
+                .iter()
+                .all(|predicate| eval_predicate(index, record, predicate))
+        })
+        .collect::<Vec<_>>();
+
+    if let Aggregation::Select = parsed.aggregation {
+        for record in matched.iter().copied() {
+            match resolve_path(index, record, &parsed.field) {
+                Some(value) => println!("{}\t{value}", record.id),
+                None => println!("{}\t<null>", record.id),
+            }
+        }
+        return;
+    }
+
+    let Some(group_field) = &parsed.group_by else {
+        if let Aggregation::Count = parsed.aggregation {
+            println!("{}", matched.len());
+            return;
+        }
+        let values = matched
+            .iter()
+            .copied()
+            .filter_map(|record| resolve_numeric_path(index, record, &parsed.field))
+            .collect::<Vec<_>>();
+        match fold_aggregate(parsed.aggregation, &values) {
+            Some(result) => println!("{result}"),
+            None => println!("no results"),
+        }
+        return;
+    };
+
+    let mut groups: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    for record in matched.iter().copied() {
+        let Some(key) = resolve_path(index, record, group_field) else {
+            continue;
+        };
+        let group = groups.entry(key).or_default();
+        if let Aggregation::Count = parsed.aggregation {
+            group.push(1.0);
+        } else if let Some(value) = resolve_numeric_path(index, record, &parsed.field) {
+            group.push(value);
+        }
+    }
+    for (key, values) in groups {
+        match parsed.aggregation {
+            Aggregation::Count => println!("{key}\t{}", values.len()),
+            aggregation => match fold_aggregate(aggregation, &values) {
+                Some(result) => println!("{key}\t{result}"),
+                None => println!("{key}\tno results"),
+            },
+        }
+    }
+}
+
+const SEARCH_INDEX_FILE: &str = "gddb_search_index.bin";
+
+/// One occurrence of a token in a record's field, carrying enough of the original
+/// value to show a snippet without re-fetching the record at query time.
+struct Posting {
+    record_id: String,
+    field: String,
+    snippet: String,
+}
+
+/// An inverted index from lowercased token to every (record, field) it appears in.
+struct SearchIndex {
+    words: FstMap<Vec<u8>>,
+    postings: Vec<Vec<Posting>>,
+}
+
+fn search_index_path(install_path: &Path, xpac: Option<usize>) -> PathBuf {
+    match xpac {
+        Some(n) => install_path.join(format!("gddb_search_index_xpac{n}.bin")),
+        None => install_path.join(SEARCH_INDEX_FILE),
+    }
+}
+
+fn xpac_db_paths(install_path: &Path, xpac: Option<usize>) -> Vec<PathBuf> {
+    let names: &[&str] = match xpac {
+        Some(0) => &[DB_GD],
+        Some(1) => &[DB_AOM],
+        Some(2) => &[DB_FG],
+        Some(3) => &[DB_FOA],
+        _ => &[DB_GD, DB_AOM, DB_FG, DB_FOA],
+    };
+    names.iter().map(|name| install_path.join(name)).collect()
+}
+
+fn newest_mtime(paths: &[PathBuf]) -> Option<std::time::SystemTime> {
+    paths
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).ok()?.modified().ok())
+        .max()
 }
 
-fn iter_records<T: BufRead + Seek>(
+/// Split on non-alphanumeric boundaries and lowercase, so `"retaliationDamage"` and
+/// `assets/textures/foo.tex` both yield searchable word-shaped tokens.
+fn tokenize_text(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|s| !s.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Name/description-shaped fields are weighted above raw script/texture paths so a
+/// match in `itemNameTag` outranks an incidental hit in a FX script reference.
+fn field_weight(field: &str) -> u32 {
+    let lower = field.to_lowercase();
+    if lower.contains("name") || lower.contains("desc") {
+        3
+    } else {
+        1
+    }
+}
+
+fn build_search_index<T: BufRead + Seek>(arz: &mut [Database<T>]) -> SearchIndex {
+    let mut words: BTreeMap<String, Vec<Posting>> = BTreeMap::new();
+    for record in records_by_xpac(arz, |_, _| true).into_iter().flatten() {
+        for (field, value) in &record.data {
+            let text = value.to_string();
+            let snippet: String = text.chars().take(80).collect();
+            let mut seen = HashSet::new();
+            for token in tokenize_text(&text) {
+                if seen.insert(token.clone()) {
+                    words.entry(token).or_default().push(Posting {
+                        record_id: record.id.clone(),
+                        field: field.clone(),
+                        snippet: snippet.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    let mut builder = MapBuilder::memory();
+    let mut postings = Vec::with_capacity(words.len());
+    for (word, entries) in words {
+        builder
+            .insert(&word, postings.len() as u64)
+            .expect("words are inserted in sorted order");
+        postings.push(entries);
+    }
+    let bytes = builder.into_inner().expect("fst builder finishes cleanly");
+    let words = FstMap::new(bytes).expect("just-built fst bytes form a valid map");
+    SearchIndex { words, postings }
+}
+
+fn save_search_index(path: &Path, index: &SearchIndex) -> std::io::Result<()> {
+    let fst_bytes = index.words.as_fst().as_bytes();
+    let mut out = Vec::with_capacity(fst_bytes.len());
+    out.extend_from_slice(&(fst_bytes.len() as u64).to_le_bytes());
+    out.extend_from_slice(fst_bytes);
+    for entries in &index.postings {
+        let joined = entries
+            .iter()
+            .map(|p| format!("{}\u{1}{}\u{1}{}", p.record_id, p.field, p.snippet))
+            .collect::<Vec<_>>()
+            .join("\u{2}");
+        out.extend_from_slice(&(joined.len() as u64).to_le_bytes());
+        out.extend_from_slice(joined.as_bytes());
+    }
+    std::fs::write(path, out)
+}
+
+fn load_search_index(path: &Path) -> std::io::Result<SearchIndex> {
+    let bytes = std::fs::read(path)?;
+    let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+
+    let mut cursor = &bytes[..];
+    let fst_len = read_u64(&mut cursor)? as usize;
+    let fst_bytes = cursor
+        .get(..fst_len)
+        .ok_or_else(|| invalid("truncated search index"))?;
+    let words = FstMap::new(fst_bytes.to_vec()).map_err(invalid)?;
+    cursor = &cursor[fst_len..];
+
+    let mut postings = Vec::new();
+    while !cursor.is_empty() {
+        let len = read_u64(&mut cursor)? as usize;
+        let raw = cursor
+            .get(..len)
+            .ok_or_else(|| invalid("truncated search index"))?;
+        let joined = std::str::from_utf8(raw).map_err(invalid)?;
+        let entries = joined
+            .split('\u{2}')
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let mut parts = entry.splitn(3, '\u{1}');
+                Some(Posting {
+                    record_id: parts.next()?.to_string(),
+                    field: parts.next()?.to_string(),
+                    snippet: parts.next().unwrap_or("").to_string(),
+                })
+            })
+            .collect();
+        postings.push(entries);
+        cursor = &cursor[len..];
+    }
+    Ok(SearchIndex { words, postings })
+}
+
+/// Load the sidecar search index, rebuilding it when missing or older than any .arz it
+/// covers so a fresh install re-tokenizes once instead of silently serving stale hits.
+fn load_or_build_search_index<T: BufRead + Seek>(
     arz: &mut [Database<T>],
-    p: impl Fn(&str, &RawRecord) -> bool,
-) -> impl Iterator<Item = Record> + '_ {
-    records_by_xpac(arz, p)
+    install_path: &Path,
+    xpac: Option<usize>,
+) -> SearchIndex {
+    let path = search_index_path(install_path, xpac);
+    let db_paths = xpac_db_paths(install_path, xpac);
+    let index_mtime = std::fs::metadata(&path).ok().and_then(|meta| meta.modified().ok());
+    if let (Some(index_mtime), Some(newest_db)) = (index_mtime, newest_mtime(&db_paths)) {
+        if index_mtime >= newest_db {
+            if let Ok(index) = load_search_index(&path) {
+                return index;
+            }
+        }
+    }
+    let index = build_search_index(arz);
+    if let Err(e) = save_search_index(&path, &index) {
+        eprintln!(
+            "Warning: could not write search index cache to {}: {e}",
+            path.display()
+        );
+    }
+    index
+}
+
+/// Lowercase and deduplicate search terms so a repeated term (`search foo foo`) can't
+/// inflate the AND-match threshold past what `matched_terms` (a set of distinct terms
+/// seen per record) can ever reach.
+fn dedupe_terms(terms: Vec<String>) -> Vec<String> {
+    terms
+        .iter()
+        .map(|t| t.to_lowercase())
+        .collect::<HashSet<_>>()
         .into_iter()
-        .map(|db| db.into_iter())
-        .flatten()
+        .collect()
 }
 
-fn iter_record_ids<T: BufRead + Seek>(
+/// Answer an AND-query against the inverted index: a record must have at least one
+/// field matching every term, ranked by how many distinct fields carried a match
+/// (weighted by `field_weight`) since the term count is constant across AND results.
+fn run_search<T: BufRead + Seek>(
     arz: &mut [Database<T>],
-) -> impl Iterator<Item = String> + '_ {
-    match load_raws_by_xpac(arz)
+    install_path: &Path,
+    xpac: Option<usize>,
+    terms: Vec<String>,
+) {
+    let index = load_or_build_search_index(arz, install_path, xpac);
+    let terms = dedupe_terms(terms);
+
+    let mut hits: HashMap<String, (HashSet<String>, BTreeMap<String, String>, u32)> = HashMap::new();
+    for term in &terms {
+        let Some(posting_id) = index.words.get(term) else {
+            continue;
+        };
+        for posting in &index.postings[posting_id as usize] {
+            let (matched_terms, fields, weight) = hits
+                .entry(posting.record_id.clone())
+                .or_insert_with(|| (HashSet::new(), BTreeMap::new(), 0));
+            matched_terms.insert(term.clone());
+            fields
+                .entry(posting.field.clone())
+                .or_insert_with(|| posting.snippet.clone());
+            *weight += field_weight(&posting.field);
+        }
+    }
+
+    let mut results: Vec<_> = hits
         .into_iter()
-        .enumerate()
-        .map(|(i, raws)| {
-            raws.into_iter()
-                .map(|raw| arz[i].record_id(&raw))
-                .collect::<Result<Vec<_>, _>>()
-        })
-        .collect::<Result<Vec<_>, _>>()
-    {
-        Ok(ids) => ids.into_iter().flat_map(|ids| ids.into_iter()),
-        Err(e) => {
-            eprintln!("Error parsing database records: {e}");
-            std::process::exit(1);
+        .filter(|(_, (matched_terms, ..))| matched_terms.len() == terms.len())
+        .collect();
+    let weighted_fields =
+        |fields: &BTreeMap<String, String>| fields.keys().map(|field| field_weight(field)).sum::<u32>();
+    results.sort_by(|(a_id, (_, a_fields, a_weight)), (b_id, (_, b_fields, b_weight))| {
+        weighted_fields(b_fields)
+            .cmp(&weighted_fields(a_fields))
+            .then_with(|| b_weight.cmp(a_weight))
+            .then_with(|| a_id.cmp(b_id))
+    });
+
+    if results.is_empty() {
+        println!("No matches found");
+        return;
+    }
+    for (record_id, (_, fields, _)) in results {
+        println!("{record_id}");
+        for (field, snippet) in fields {
+            println!("  {field}: {snippet}");
         }
     }
 }
@@ -317,3 +1429,161 @@ fn read_item_tags(install_path: PathBuf) -> HashMap<String, String> {
 
     item_tags
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// R -> {P1, P2}, P1 -> {M, LeafX}, M -> {P1} (a cycle between P1 and M), P2 -> {M}.
+    /// M is reachable both through the cycle (via P1) and through a cycle-free path (via
+    /// P2), so a memo keyed only on record id must not let the truncated expansion from
+    /// the first path leak into the second: LeafX should end up with the full 0.5, not
+    /// half of it.
+    #[test]
+    fn resolve_distribution_does_not_cross_contaminate_cycle_and_non_cycle_paths() {
+        let mut children = |id: &str| -> Vec<(String, f64)> {
+            match id {
+                "R" => vec![("P1".to_string(), 1.0), ("P2".to_string(), 1.0)],
+                "P1" => vec![("M".to_string(), 1.0), ("LeafX".to_string(), 1.0)],
+                "M" => vec![("P1".to_string(), 1.0)],
+                "P2" => vec![("M".to_string(), 1.0)],
+                _ => vec![],
+            }
+        };
+        let mut visiting = HashSet::new();
+        let mut memo = HashMap::new();
+        let (distribution, _) = resolve_distribution("R", &mut visiting, &mut memo, &mut children);
+
+        let leaf_x = distribution
+            .iter()
+            .find(|(item, _)| item == "LeafX")
+            .map(|(_, probability)| *probability)
+            .unwrap_or(0.0);
+        assert!(
+            (leaf_x - 0.5).abs() < 1e-9,
+            "expected LeafX == 0.5, got {leaf_x}"
+        );
+    }
+
+    #[test]
+    fn dedupe_terms_collapses_repeats_case_insensitively() {
+        let mut terms = dedupe_terms(vec!["Foo".to_string(), "foo".to_string(), "bar".to_string()]);
+        terms.sort();
+        assert_eq!(terms, vec!["bar".to_string(), "foo".to_string()]);
+    }
+
+    #[test]
+    fn tokenize_keeps_a_quoted_value_with_embedded_spaces_as_one_token() {
+        let tokens = tokenize(r#"select(*) where itemNameTag = "Boots of the Whale""#);
+        assert_eq!(
+            tokens,
+            vec![
+                "select".to_string(),
+                "(".to_string(),
+                "*".to_string(),
+                ")".to_string(),
+                "where".to_string(),
+                "itemNameTag".to_string(),
+                "=".to_string(),
+                "Boots of the Whale".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_splits_glued_operators_with_no_surrounding_whitespace() {
+        let tokens = tokenize("where a!=1 and b<=2");
+        assert_eq!(
+            tokens,
+            vec![
+                "where".to_string(),
+                "a".to_string(),
+                "!=".to_string(),
+                "1".to_string(),
+                "and".to_string(),
+                "b".to_string(),
+                "<=".to_string(),
+                "2".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_empty_query_yields_no_tokens() {
+        assert_eq!(tokenize(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn parse_query_rejects_a_missing_closing_paren() {
+        let tokens = tokenize("count(recordId");
+        let err = parse_query(&tokens).expect_err("missing `)` should be rejected");
+        assert!(err.contains("`)`"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn parse_query_rejects_group_without_by() {
+        let tokens = tokenize("count(*) group recordId");
+        let err = parse_query(&tokens).expect_err("missing `by` should be rejected");
+        assert!(err.contains("`by`"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn parse_query_rejects_an_empty_query_string() {
+        let tokens = tokenize("");
+        let err = parse_query(&tokens).expect_err("empty query should be rejected");
+        assert!(err.contains("select/count/min/max/sum/avg"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn parse_query_rejects_group_by_on_select() {
+        let tokens = tokenize("select(*) group by itemClassification");
+        let err = parse_query(&tokens).expect_err("`group by` on `select` should be rejected");
+        assert!(err.contains("group by"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn tag_index_round_trips_through_save_and_load() {
+        let mut tags = HashMap::new();
+        tags.insert("tagBoots".to_string(), "Boots of the Whale".to_string());
+        tags.insert("tagLamp".to_string(), "Whale Oil Lamp".to_string());
+        let index = build_tag_index(&tags);
+
+        let path = std::env::temp_dir().join(format!("gddb_test_tag_index_{}.bin", std::process::id()));
+        save_tag_index(&path, &index).expect("save should succeed");
+        let loaded = load_tag_index(&path).expect("load should succeed");
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(index.postings, loaded.postings);
+        assert_eq!(index.words.as_fst().as_bytes(), loaded.words.as_fst().as_bytes());
+    }
+
+    #[test]
+    fn search_index_round_trips_through_save_and_load() {
+        let mut builder = MapBuilder::memory();
+        builder.insert("whale", 0u64).expect("words are inserted in sorted order");
+        let bytes = builder.into_inner().expect("fst builder finishes cleanly");
+        let words = FstMap::new(bytes).expect("just-built fst bytes form a valid map");
+        let postings = vec![vec![Posting {
+            record_id: "records/items/boots".to_string(),
+            field: "itemNameTag".to_string(),
+            snippet: "Boots of the Whale".to_string(),
+        }]];
+        let index = SearchIndex { words, postings };
+
+        let path = std::env::temp_dir().join(format!("gddb_test_search_index_{}.bin", std::process::id()));
+        save_search_index(&path, &index).expect("save should succeed");
+        let loaded = load_search_index(&path).expect("load should succeed");
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(index.words.as_fst().as_bytes(), loaded.words.as_fst().as_bytes());
+        assert_eq!(index.postings.len(), loaded.postings.len());
+        for (orig, got) in index.postings.iter().zip(loaded.postings.iter()) {
+            assert_eq!(orig.len(), got.len());
+            for (orig, got) in orig.iter().zip(got.iter()) {
+                assert_eq!(orig.record_id, got.record_id);
+                assert_eq!(orig.field, got.field);
+                assert_eq!(orig.snippet, got.snippet);
+            }
+        }
+    }
+}